@@ -8,6 +8,14 @@
 /// - Query IP information using a specified endpoint.
 /// - Store IP information using a custom store.
 /// - Option to use the `X-Forwarded-For` header for IP address extraction.
+/// - Expose the resolved `IPInfo` to handlers via the `ClientIpInfo` extractor.
+/// - Optional TTL cache so repeat visitors skip the network round-trip.
+/// - Configurable `FailurePolicy` so a missing address or a flaky IP API doesn't
+///   have to take down the request.
+/// - Geo/risk-based request filtering, turning the middleware into an IP-based
+///   firewall layer.
+/// - Async, fallible stores, optionally decoupled from the response path via
+///   `IPQuery::spawn_store`.
 ///
 /// ## Usage Example
 /// ```rust
@@ -18,9 +26,11 @@
 /// struct MyStore;
 ///
 /// impl IPQueryStore for MyStore {
-///     fn store(&self, ip_info: ipapi::IPInfo) -> Result<(), std::io::Error> {
-///         println!("{:?}", ip_info);
-///         Ok(())
+///     fn store(&self, ip_info: std::sync::Arc<ipapi::IPInfo>) -> actix_ipquery::StoreFuture {
+///         Box::pin(async move {
+///             println!("{:?}", ip_info);
+///             Ok(())
+///         })
 ///     }
 /// }
 ///
@@ -36,21 +46,43 @@
 ///     .await
 /// }
 /// ```
-use ipapi::{query_ip_with_endpoint, Error as ReqwestError, IPInfo};
+use ipapi::IPInfo;
+use reqwest::Error as ReqwestError;
+use std::collections::{HashMap, HashSet};
 use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use actix_web::{
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error,
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::StatusCode,
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
 };
 use futures_util::future::LocalBoxFuture;
 
+/// `ipapi::query_ip` hardcodes ipquery.io's own base URL, so `IPQuery::endpoint`
+/// needs its own copy of the same request/deserialize logic against a
+/// configurable one.
+async fn query_ip_with_endpoint(ip: &str, endpoint: &str) -> Result<IPInfo, ReqwestError> {
+    reqwest::get(format!("{}{}", endpoint, ip))
+        .await?
+        .json::<IPInfo>()
+        .await
+}
+
 /// The IPQuery struct that implements actix-web's middleware.
 #[derive(Clone)]
 pub struct IPQuery<T: IPQueryStore> {
     endpoint: String,
     store: T,
     forwarded_for: bool,
+    cache: Option<Arc<IpCache>>,
+    store_on_cache_hit: bool,
+    on_error: FailurePolicy,
+    filter: Option<FilterFn>,
+    spawn_store: bool,
 }
 impl<T: IPQueryStore> IPQuery<T> {
     /// Create a new IPQuery middleware
@@ -59,6 +91,11 @@ impl<T: IPQueryStore> IPQuery<T> {
             endpoint: "https://api.ipquery.io/".to_owned(),
             store,
             forwarded_for: false,
+            cache: None,
+            store_on_cache_hit: true,
+            on_error: FailurePolicy::default(),
+            filter: None,
+            spawn_store: false,
         }
     }
     /// Set the endpoint for the IP query
@@ -71,6 +108,123 @@ impl<T: IPQueryStore> IPQuery<T> {
         self.forwarded_for = y;
         self
     }
+    /// Memoize `query_ip` results per IP address for `ttl`, holding at most `capacity`
+    /// entries, so the inner HTTP lookup is skipped for repeat visitors.
+    pub fn cache(&mut self, ttl: Duration, capacity: usize) -> &mut Self {
+        self.cache = Some(Arc::new(IpCache::new(ttl, capacity)));
+        self
+    }
+    /// Whether a cache hit should still invoke the configured `IPQueryStore` (the
+    /// default) or only store on a fresh lookup.
+    pub fn store_on_cache_hit(&mut self, y: bool) -> &mut Self {
+        self.store_on_cache_hit = y;
+        self
+    }
+    /// Control what happens when the IP address can't be resolved, the lookup
+    /// fails, or the store fails. Defaults to `FailurePolicy::FailOpen`, which is the right choice for
+    /// analytics/logging use cases where a flaky IP API shouldn't take down
+    /// otherwise-healthy requests.
+    pub fn on_error(&mut self, policy: FailurePolicy) -> &mut Self {
+        self.on_error = policy;
+        self
+    }
+    /// Gate requests on the resolved `IPInfo` before they reach the inner service.
+    /// Composes with any previously configured filter (including one set by the
+    /// convenience helpers below): the request is denied if either filter denies
+    /// it, and the first one to deny decides the status and body. This lets
+    /// `.block_countries([...]).block_proxies()` chain without either rule
+    /// silently dropping the other.
+    pub fn filter(&mut self, f: FilterFn) -> &mut Self {
+        self.filter = Some(match self.filter.take() {
+            Some(existing) => Arc::new(move |info: &IPInfo| match existing(info) {
+                FilterDecision::Allow => f(info),
+                deny => deny,
+            }),
+            None => f,
+        });
+        self
+    }
+    /// Reject requests whose resolved country code is one of `countries`
+    /// (case-insensitive, e.g. `"RU"`, `"KP"`). ipquery doesn't always return
+    /// location data; requests we can't place in a country are let through,
+    /// since we have no basis to block them.
+    pub fn block_countries<I, S>(&mut self, countries: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let blocked: HashSet<String> = countries
+            .into_iter()
+            .map(|c| c.into().to_uppercase())
+            .collect();
+        self.filter(Arc::new(move |info: &IPInfo| {
+            let is_blocked = info
+                .location
+                .as_ref()
+                .and_then(|l| l.country_code.as_deref())
+                .is_some_and(|c| blocked.contains(&c.to_uppercase()));
+            if is_blocked {
+                FilterDecision::Deny {
+                    status: StatusCode::FORBIDDEN,
+                    body: "Access from your country is not permitted".to_owned(),
+                }
+            } else {
+                FilterDecision::Allow
+            }
+        }))
+    }
+    /// Allow requests only from `countries` (case-insensitive), rejecting everything
+    /// else, including requests whose country couldn't be determined.
+    pub fn allow_only_countries<I, S>(&mut self, countries: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let allowed: HashSet<String> = countries
+            .into_iter()
+            .map(|c| c.into().to_uppercase())
+            .collect();
+        self.filter(Arc::new(move |info: &IPInfo| {
+            let is_allowed = info
+                .location
+                .as_ref()
+                .and_then(|l| l.country_code.as_deref())
+                .is_some_and(|c| allowed.contains(&c.to_uppercase()));
+            if is_allowed {
+                FilterDecision::Allow
+            } else {
+                FilterDecision::Deny {
+                    status: StatusCode::FORBIDDEN,
+                    body: "Access from your country is not permitted".to_owned(),
+                }
+            }
+        }))
+    }
+    /// Reject requests that ipquery flags as a VPN, proxy, or Tor exit node. Requests
+    /// ipquery couldn't score for risk are let through rather than blocked.
+    pub fn block_proxies(&mut self) -> &mut Self {
+        self.filter(Arc::new(|info: &IPInfo| {
+            let is_risky = info.risk.as_ref().is_some_and(|r| {
+                r.is_vpn.unwrap_or(false)
+                    || r.is_proxy.unwrap_or(false)
+                    || r.is_tor.unwrap_or(false)
+            });
+            if is_risky {
+                FilterDecision::Deny {
+                    status: StatusCode::FORBIDDEN,
+                    body: "Proxy, VPN, and Tor traffic is not permitted".to_owned(),
+                }
+            } else {
+                FilterDecision::Allow
+            }
+        }))
+    }
+    /// Run the configured store detached via `actix_web::rt::spawn` instead of
+    /// awaiting it on the response path, so store latency can't delay the response.
+    pub fn spawn_store(&mut self, y: bool) -> &mut Self {
+        self.spawn_store = y;
+        self
+    }
     /// Finish the configuration and return the middleware
     pub fn finish(&self) -> IPQuery<T> {
         self.clone()
@@ -78,16 +232,172 @@ impl<T: IPQueryStore> IPQuery<T> {
     async fn query_ip(&self, ip: &str) -> Result<IPInfo, ReqwestError> {
         query_ip_with_endpoint(ip, &self.endpoint).await
     }
+    /// Resolve the `IPInfo` for `ip`, preferring a live cache entry when one is
+    /// configured and not yet expired. Returns whether the value came from the cache.
+    ///
+    /// Returned as an `Arc` since `IPInfo` itself isn't `Clone`, and the same
+    /// lookup is shared between the cache, the `ClientIpInfo` extractor, and the
+    /// configured store.
+    async fn resolve(&self, ip: &str) -> Result<(Arc<IPInfo>, bool), ReqwestError> {
+        if let Some(cache) = &self.cache {
+            if let Some(info) = cache.get(ip) {
+                return Ok((info, true));
+            }
+        }
+        let info = Arc::new(self.query_ip(ip).await?);
+        if let Some(cache) = &self.cache {
+            cache.insert(ip.to_owned(), info.clone());
+        }
+        Ok((info, false))
+    }
+    /// Decide how to respond when the client's IP/info couldn't be resolved
+    /// (`err` is `NoAddress` or `Lookup`). Defers to `on_error` first so
+    /// `FailClosed`/`Custom` are honored with their configured status/body;
+    /// only when `on_error` would let the request through (`FailOpen`) and a
+    /// filter is configured do we force a deny, since a configured filter is a
+    /// security gate that an unresolvable address must not silently bypass.
+    fn deny_unresolvable(&self, err: PolicyError) -> Option<HttpResponse> {
+        self.on_error.respond(err).or_else(|| {
+            self.filter
+                .is_some()
+                .then(|| HttpResponse::Forbidden().body("Unable to verify client IP"))
+        })
+    }
+    /// Run the configured store, either inline or detached, depending on `spawn_store`.
+    async fn dispatch_store(&self, ip_info: Arc<IPInfo>) -> Result<(), StoreError> {
+        if self.spawn_store {
+            let fut = self.store.store(ip_info);
+            actix_web::rt::spawn(async move {
+                let _ = fut.await;
+            });
+            Ok(())
+        } else {
+            self.store.store(ip_info).await
+        }
+    }
+}
+
+/// The failure a `FailurePolicy` is reacting to, passed to `FailurePolicy::Custom`.
+pub enum PolicyError<'a> {
+    /// Neither the forwarded-for header nor the peer address yielded an address to
+    /// look up.
+    NoAddress,
+    /// The IP lookup itself failed.
+    Lookup(&'a ReqwestError),
+    /// The configured `IPQueryStore::store` failed.
+    Store(&'a StoreError),
+}
+
+/// What to do when the IP address can't be resolved, `query_ip` fails, or the
+/// configured store fails.
+#[derive(Clone, Default)]
+pub enum FailurePolicy {
+    /// Let the request proceed untouched and skip `store`. The sensible default for
+    /// analytics/logging use cases. When a filter is configured, a request whose
+    /// address or lookup can't be resolved is rejected with `403 Forbidden` instead.
+    #[default]
+    FailOpen,
+    /// Respond with the given status code: before the request reaches the inner
+    /// service for a missing address or failed lookup, and in place of the inner
+    /// service's response for a failed store.
+    FailClosed { status: StatusCode },
+    /// Respond with a response built from the underlying error, at the same points
+    /// as `FailClosed`.
+    Custom(Arc<dyn Fn(PolicyError) -> HttpResponse + Send + Sync>),
+}
+
+impl FailurePolicy {
+    /// Decide how to respond to `err`, or `None` to let the request proceed
+    /// (`FailOpen`).
+    fn respond(&self, err: PolicyError) -> Option<HttpResponse> {
+        match self {
+            FailurePolicy::FailOpen => None,
+            FailurePolicy::FailClosed { status } => Some(HttpResponse::new(*status)),
+            FailurePolicy::Custom(f) => Some(f(err)),
+        }
+    }
+}
+
+/// A closure passed to `IPQuery::filter`, gating requests on the resolved `IPInfo`.
+pub type FilterFn = Arc<dyn Fn(&IPInfo) -> FilterDecision + Send + Sync>;
+
+/// Decision produced by an `IPQuery::filter` closure for a resolved `IPInfo`.
+#[derive(Clone)]
+pub enum FilterDecision {
+    /// Let the request continue to the inner service.
+    Allow,
+    /// Reject the request with the given status and body, without calling the inner
+    /// service. `store` still runs (on a cache hit, only if `store_on_cache_hit` is
+    /// set), so blocked attempts are recorded.
+    Deny { status: StatusCode, body: String },
+}
+
+/// A small TTL cache mapping IP addresses to their last resolved value, shared
+/// across clones of the middleware. Generic over the cached value (defaulting to
+/// `Arc<IPInfo>`, since `IPInfo` itself isn't `Clone`) so it can also be
+/// exercised in tests without constructing a real `IPInfo`.
+struct IpCache<V = Arc<IPInfo>> {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, (V, Instant)>>,
+}
+
+impl<V: Clone> IpCache<V> {
+    fn new(ttl: Duration, capacity: usize) -> Self {
+        IpCache {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, ip: &str) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(ip) {
+            Some((info, inserted)) if inserted.elapsed() < self.ttl => Some(info.clone()),
+            Some(_) => {
+                entries.remove(ip);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, ip: String, info: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            let expired: Vec<String> = entries
+                .iter()
+                .filter(|(_, (_, inserted))| inserted.elapsed() >= self.ttl)
+                .map(|(ip, _)| ip.clone())
+                .collect();
+            if !expired.is_empty() {
+                for ip in expired {
+                    entries.remove(&ip);
+                }
+            } else if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, (_, inserted))| *inserted)
+                .map(|(ip, _)| ip.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(ip, (info, Instant::now()));
+    }
 }
 impl<S, B, T> Transform<S, ServiceRequest> for IPQuery<T>
 where
     T: IPQueryStore + 'static,
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
     T: IPQueryStore + Clone,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type InitError = ();
     type Transform = IPQueryMiddleware<S, T>;
@@ -95,8 +405,8 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(IPQueryMiddleware {
-            service,
-            ip_query: std::sync::Arc::new(self.clone()),
+            service: Rc::new(service),
+            ip_query: Arc::new(self.clone()),
         }))
     }
 }
@@ -105,18 +415,18 @@ pub struct IPQueryMiddleware<S, T>
 where
     T: IPQueryStore,
 {
-    service: S,
-    ip_query: std::sync::Arc<IPQuery<T>>,
+    service: Rc<S>,
+    ip_query: Arc<IPQuery<T>>,
 }
 
 impl<S, B, T> Service<ServiceRequest> for IPQueryMiddleware<S, T>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
     T: IPQueryStore + Clone + 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
     forward_ready!(service);
@@ -125,49 +435,583 @@ where
         let ip = if self.ip_query.forwarded_for {
             req.connection_info()
                 .realip_remote_addr()
-                .unwrap()
-                .to_string()
+                .map(|addr| addr.to_string())
         } else {
-            match req.peer_addr() {
-                Some(addr) => addr.ip().to_string(),
-                None => {
-                    return Box::pin(async {
-                        Err(Error::from(actix_web::error::ErrorInternalServerError(
-                            "No peer address",
-                        )))
-                    })
-                }
+            None
+        }
+        .or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()));
+
+        let service = self.service.clone();
+        let ip_query_clone = self.ip_query.clone();
+
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                // Neither the forwarded-for header nor the peer address was available;
+                // there's nothing to look up, so fall back to the failure policy
+                // instead of panicking or hard-failing every such request.
+                return Box::pin(async move {
+                    match ip_query_clone.deny_unresolvable(PolicyError::NoAddress) {
+                        Some(resp) => Ok(req.into_response(resp).map_into_right_body()),
+                        None => Ok(service.call(req).await?.map_into_left_body()),
+                    }
+                });
             }
         };
 
-        let fut = self.service.call(req);
-        let ip_query_clone = self.ip_query.clone();
         Box::pin(async move {
-            let res = fut.await?;
-            let ip_info = match ip_query_clone.query_ip(&ip).await {
-                Ok(info) => info,
+            // Resolved before the inner service runs, and left unset on failure, so a
+            // handler using `ClientIpInfo` gets a clean extractor error instead of a panic.
+            let ip_info = match ip_query_clone.resolve(&ip).await {
+                Ok(info) => Some(info),
                 Err(e) => {
-                    return Err(Error::from(actix_web::error::ErrorInternalServerError(
-                        e.to_string(),
-                    )))
+                    if let Some(resp) = ip_query_clone.deny_unresolvable(PolicyError::Lookup(&e)) {
+                        return Ok(req.into_response(resp).map_into_right_body());
+                    }
+                    None
                 }
             };
-            ip_query_clone.store.store(ip_info)?;
-            Ok(res)
+
+            if let Some((info, _)) = ip_info.clone() {
+                req.extensions_mut().insert(ClientIpInfo(info));
+            }
+
+            if let Some((info, was_cached)) = &ip_info {
+                if let Some(filter) = &ip_query_clone.filter {
+                    if let FilterDecision::Deny { status, body } = filter(info) {
+                        // `store` here is only for the record of a blocked attempt; a
+                        // store failure must not change the access-control decision
+                        // that was already made, so it's ignored rather than routed
+                        // through `on_error`.
+                        if !was_cached || ip_query_clone.store_on_cache_hit {
+                            let _ = ip_query_clone.dispatch_store(info.clone()).await;
+                        }
+                        return Ok(req
+                            .into_response(HttpResponse::build(status).body(body))
+                            .map_into_right_body());
+                    }
+                }
+            }
+
+            let res = service.call(req).await?;
+
+            // Dispatched after the inner service runs. Under `FailOpen` a store failure
+            // leaves the inner service's response as is; otherwise the policy's
+            // response replaces it. `req` is gone by now, so the response is rebuilt
+            // from `res`.
+            if let Some((info, was_cached)) = &ip_info {
+                if !was_cached || ip_query_clone.store_on_cache_hit {
+                    if let Err(e) = ip_query_clone.dispatch_store(info.clone()).await {
+                        if let Some(resp) = ip_query_clone.on_error.respond(PolicyError::Store(&e))
+                        {
+                            return Ok(res.into_response(resp).map_into_right_body());
+                        }
+                    }
+                }
+            }
+
+            Ok(res.map_into_left_body())
         })
     }
 }
 
+/// The `IPInfo` resolved for the current request, made available to handlers through
+/// actix-web's extractor mechanism.
+///
+/// ```rust,ignore
+/// async fn handler(info: ClientIpInfo) -> impl Responder {
+///     format!("{:?}", info.0)
+/// }
+/// ```
+#[derive(Clone)]
+pub struct ClientIpInfo(pub Arc<IPInfo>);
+
+impl FromRequest for ClientIpInfo {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<ClientIpInfo>()
+                .cloned()
+                .ok_or_else(|| {
+                    actix_web::error::ErrorInternalServerError(
+                        "IP information is not available for this request",
+                    )
+                }),
+        )
+    }
+}
+
+/// The future type returned by `IPQueryStore::store`.
+pub type StoreFuture = LocalBoxFuture<'static, Result<(), StoreError>>;
+
+/// Error returned by an `IPQueryStore::store` implementation, inspectable by the
+/// configured `FailurePolicy`.
+#[derive(Debug)]
+pub enum StoreError {
+    /// A store that does blocking-style I/O under the hood.
+    Io(std::io::Error),
+    /// Any other failure, carrying a human-readable message.
+    Other(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "{}", e),
+            StoreError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl actix_web::ResponseError for StoreError {}
+
 /// Define the IPQueryStore trait
-pub trait IPQueryStore: Send + Sync + Clone {
-    fn store(&self, ip_info: IPInfo) -> Result<(), std::io::Error>;
+pub trait IPQueryStore: Send + Sync + Clone + 'static {
+    /// Persist the resolved `IPInfo`. Returning a boxed future lets implementations
+    /// do real async I/O (database, Redis, remote API) inside the `LocalBoxFuture`
+    /// that `call` already returns, instead of blocking the request task.
+    ///
+    /// Takes an `Arc<IPInfo>` rather than an owned value since `IPInfo` isn't
+    /// `Clone` and the same lookup is also shared with the cache and the
+    /// `ClientIpInfo` extractor.
+    fn store(&self, ip_info: Arc<IPInfo>) -> StoreFuture;
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::thread::sleep;
+
     #[tokio::test]
     async fn my_ip() {
         let ip = ipapi::query_own_ip().await.unwrap();
         println!("{:?}", ip);
     }
+
+    // `IpCache` is exercised with plain `i32` values rather than a real `IPInfo`:
+    // the cache logic is generic over the stored value and doesn't need a live
+    // `ipapi` response to prove TTL expiry and eviction.
+
+    #[test]
+    fn cache_hit_before_ttl_expiry() {
+        let cache: IpCache<i32> = IpCache::new(Duration::from_secs(60), 10);
+        cache.insert("1.1.1.1".to_owned(), 42);
+        assert_eq!(cache.get("1.1.1.1"), Some(42));
+    }
+
+    #[test]
+    fn cache_miss_after_ttl_expiry() {
+        let cache: IpCache<i32> = IpCache::new(Duration::from_millis(10), 10);
+        cache.insert("1.1.1.1".to_owned(), 42);
+        sleep(Duration::from_millis(30));
+        assert_eq!(cache.get("1.1.1.1"), None);
+    }
+
+    #[test]
+    fn cache_miss_for_unknown_ip() {
+        let cache: IpCache<i32> = IpCache::new(Duration::from_secs(60), 10);
+        assert_eq!(cache.get("1.1.1.1"), None);
+    }
+
+    #[test]
+    fn cache_evicts_oldest_entry_when_at_capacity() {
+        let cache: IpCache<i32> = IpCache::new(Duration::from_secs(60), 2);
+        cache.insert("1.1.1.1".to_owned(), 1);
+        sleep(Duration::from_millis(5));
+        cache.insert("2.2.2.2".to_owned(), 2);
+        sleep(Duration::from_millis(5));
+        // At capacity with nothing expired, inserting a third entry should evict
+        // the oldest (1.1.1.1) rather than the most recently inserted one.
+        cache.insert("3.3.3.3".to_owned(), 3);
+
+        assert_eq!(cache.get("1.1.1.1"), None);
+        assert_eq!(cache.get("2.2.2.2"), Some(2));
+        assert_eq!(cache.get("3.3.3.3"), Some(3));
+    }
+
+    #[test]
+    fn cache_evicts_expired_entries_before_oldest_when_at_capacity() {
+        let cache: IpCache<i32> = IpCache::new(Duration::from_millis(10), 2);
+        cache.insert("1.1.1.1".to_owned(), 1);
+        sleep(Duration::from_millis(30));
+        cache.insert("2.2.2.2".to_owned(), 2);
+        // 1.1.1.1 is already expired, so it's reclaimed instead of 2.2.2.2.
+        cache.insert("3.3.3.3".to_owned(), 3);
+
+        assert_eq!(cache.get("1.1.1.1"), None);
+        assert_eq!(cache.get("2.2.2.2"), Some(2));
+        assert_eq!(cache.get("3.3.3.3"), Some(3));
+    }
+
+    // `FailurePolicy::Custom` is only tested against `PolicyError::NoAddress` and
+    // `PolicyError::Store`, which can be constructed without the external `ipapi`
+    // crate; `PolicyError::Lookup` needs a real `reqwest::Error`, which can't be
+    // built outside of an actual failed request.
+
+    #[test]
+    fn fail_open_never_responds() {
+        let policy = FailurePolicy::FailOpen;
+        assert!(policy.respond(PolicyError::NoAddress).is_none());
+        let err = StoreError::Other("write failed".to_owned());
+        assert!(policy.respond(PolicyError::Store(&err)).is_none());
+    }
+
+    #[test]
+    fn fail_closed_responds_with_configured_status() {
+        let policy = FailurePolicy::FailClosed {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let resp = policy.respond(PolicyError::NoAddress).unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let err = StoreError::Other("write failed".to_owned());
+        let resp = policy.respond(PolicyError::Store(&err)).unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn custom_policy_is_invoked_for_no_address_and_store_errors() {
+        let policy = FailurePolicy::Custom(Arc::new(|err: PolicyError| match err {
+            PolicyError::NoAddress => HttpResponse::new(StatusCode::NO_CONTENT),
+            PolicyError::Store(_) => HttpResponse::new(StatusCode::BAD_GATEWAY),
+            PolicyError::Lookup(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }));
+
+        let resp = policy.respond(PolicyError::NoAddress).unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let err = StoreError::Other("write failed".to_owned());
+        let resp = policy.respond(PolicyError::Store(&err)).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    // The `block_countries`/`allow_only_countries`/`block_proxies` filters only
+    // ever read `IPInfo::location`/`IPInfo::risk`, so fixtures are built directly
+    // rather than through a live `ipapi` lookup, same rationale as the `IpCache`
+    // tests above.
+
+    #[derive(Clone)]
+    struct NoopStore;
+    impl IPQueryStore for NoopStore {
+        fn store(&self, _ip_info: Arc<IPInfo>) -> StoreFuture {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn ip_info(country: Option<&str>, is_vpn: bool, is_proxy: bool, is_tor: bool) -> IPInfo {
+        IPInfo {
+            ip: "0.0.0.0".to_owned(),
+            isp: None,
+            location: country.map(|c| ipapi::LocationInfo {
+                country: None,
+                country_code: Some(c.to_owned()),
+                city: None,
+                state: None,
+                zipcode: None,
+                latitude: None,
+                longitude: None,
+                timezone: None,
+                localtime: None,
+            }),
+            risk: Some(ipapi::RiskInfo {
+                is_mobile: None,
+                is_vpn: Some(is_vpn),
+                is_proxy: Some(is_proxy),
+                is_tor: Some(is_tor),
+                is_datacenter: None,
+                risk_score: None,
+            }),
+        }
+    }
+
+    fn is_denied(decision: FilterDecision) -> bool {
+        matches!(decision, FilterDecision::Deny { .. })
+    }
+
+    #[test]
+    fn block_countries_denies_a_blocked_country() {
+        let mut q = IPQuery::new(NoopStore);
+        q.block_countries(["RU", "KP"]);
+        let filter = q.filter.clone().unwrap();
+
+        assert!(is_denied(filter(&ip_info(Some("ru"), false, false, false))));
+        assert!(matches!(
+            filter(&ip_info(Some("US"), false, false, false)),
+            FilterDecision::Allow
+        ));
+    }
+
+    #[test]
+    fn block_countries_allows_unknown_country() {
+        let mut q = IPQuery::new(NoopStore);
+        q.block_countries(["RU"]);
+        let filter = q.filter.clone().unwrap();
+
+        assert!(matches!(
+            filter(&ip_info(None, false, false, false)),
+            FilterDecision::Allow
+        ));
+    }
+
+    #[test]
+    fn allow_only_countries_denies_unknown_country() {
+        let mut q = IPQuery::new(NoopStore);
+        q.allow_only_countries(["US"]);
+        let filter = q.filter.clone().unwrap();
+
+        assert!(matches!(
+            filter(&ip_info(Some("US"), false, false, false)),
+            FilterDecision::Allow
+        ));
+        assert!(is_denied(filter(&ip_info(None, false, false, false))));
+        assert!(is_denied(filter(&ip_info(Some("FR"), false, false, false))));
+    }
+
+    #[test]
+    fn block_proxies_denies_vpn_proxy_and_tor() {
+        let mut q = IPQuery::new(NoopStore);
+        q.block_proxies();
+        let filter = q.filter.clone().unwrap();
+
+        assert!(is_denied(filter(&ip_info(None, true, false, false))));
+        assert!(is_denied(filter(&ip_info(None, false, true, false))));
+        assert!(is_denied(filter(&ip_info(None, false, false, true))));
+        assert!(matches!(
+            filter(&ip_info(None, false, false, false)),
+            FilterDecision::Allow
+        ));
+    }
+
+    #[test]
+    fn chained_filters_compose_instead_of_overwriting() {
+        let mut q = IPQuery::new(NoopStore);
+        q.block_countries(["RU"]);
+        q.block_proxies();
+        let filter = q.filter.clone().unwrap();
+
+        // Denied by the first filter (country) even though the second (proxy) would allow it.
+        assert!(is_denied(filter(&ip_info(Some("RU"), false, false, false))));
+        // Denied by the second filter (proxy) even though the first (country) would allow it.
+        assert!(is_denied(filter(&ip_info(Some("US"), true, false, false))));
+        // Allowed only when both filters allow it.
+        assert!(matches!(
+            filter(&ip_info(Some("US"), false, false, false)),
+            FilterDecision::Allow
+        ));
+    }
+
+    #[test]
+    fn deny_unresolvable_lets_request_through_without_a_filter() {
+        let q = IPQuery::new(NoopStore);
+        assert!(q.deny_unresolvable(PolicyError::NoAddress).is_none());
+    }
+
+    #[test]
+    fn deny_unresolvable_forces_forbidden_under_fail_open_when_filtered() {
+        let mut q = IPQuery::new(NoopStore);
+        q.block_countries(["RU"]);
+
+        let resp = q.deny_unresolvable(PolicyError::NoAddress).unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn deny_unresolvable_honors_custom_policy_over_a_configured_filter() {
+        let mut q = IPQuery::new(NoopStore);
+        q.on_error(FailurePolicy::Custom(Arc::new(|_| {
+            HttpResponse::new(StatusCode::IM_A_TEAPOT)
+        })));
+        q.block_countries(["RU"]);
+
+        let resp = q.deny_unresolvable(PolicyError::NoAddress).unwrap();
+        assert_eq!(resp.status(), StatusCode::IM_A_TEAPOT);
+    }
+
+    #[test]
+    fn deny_unresolvable_honors_fail_closed_status_over_a_configured_filter() {
+        let mut q = IPQuery::new(NoopStore);
+        q.on_error(FailurePolicy::FailClosed {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+        });
+        q.block_countries(["RU"]);
+
+        let resp = q.deny_unresolvable(PolicyError::NoAddress).unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    // End-to-end coverage for `IPQueryMiddleware::call`, driven through the real
+    // `Service` impl rather than the isolated helpers above. These tests prime the
+    // cache directly so they don't depend on a live ipquery endpoint.
+
+    #[derive(Clone)]
+    struct RecordingStore(Arc<Mutex<Vec<String>>>);
+    impl IPQueryStore for RecordingStore {
+        fn store(&self, ip_info: Arc<IPInfo>) -> StoreFuture {
+            let seen = self.0.clone();
+            Box::pin(async move {
+                seen.lock().unwrap().push(ip_info.ip.clone());
+                Ok(())
+            })
+        }
+    }
+
+    #[actix_web::test]
+    async fn client_ip_info_is_extractable_by_a_handler_and_stored_after_the_response() {
+        async fn handler(info: ClientIpInfo) -> String {
+            info.0.ip.clone()
+        }
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut q = IPQuery::new(RecordingStore(seen.clone()));
+        q.cache(Duration::from_secs(60), 10);
+        let ip_query = q.finish();
+        ip_query.cache.as_ref().unwrap().insert(
+            "127.0.0.1".to_owned(),
+            Arc::new(ip_info(Some("US"), false, false, false)),
+        );
+
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .wrap(ip_query)
+                .route("/", actix_web::web::get().to(handler)),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .peer_addr("127.0.0.1:12345".parse().unwrap())
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+
+        assert!(resp.status().is_success());
+        let body = actix_web::test::read_body(resp).await;
+        assert_eq!(body, "0.0.0.0");
+        assert_eq!(seen.lock().unwrap().as_slice(), ["0.0.0.0"]);
+    }
+
+    #[actix_web::test]
+    async fn filter_deny_short_circuits_before_the_inner_service_runs() {
+        let inner_ran = Arc::new(Mutex::new(false));
+        let inner_ran_clone = inner_ran.clone();
+
+        let mut q = IPQuery::new(NoopStore);
+        q.cache(Duration::from_secs(60), 10);
+        q.block_countries(["RU"]);
+        let ip_query = q.finish();
+        ip_query.cache.as_ref().unwrap().insert(
+            "127.0.0.1".to_owned(),
+            Arc::new(ip_info(Some("RU"), false, false, false)),
+        );
+
+        let app = actix_web::test::init_service(actix_web::App::new().wrap(ip_query).route(
+            "/",
+            actix_web::web::get().to(move || {
+                let inner_ran = inner_ran_clone.clone();
+                async move {
+                    *inner_ran.lock().unwrap() = true;
+                    "ok"
+                }
+            }),
+        ))
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .peer_addr("127.0.0.1:12345".parse().unwrap())
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(!*inner_ran.lock().unwrap());
+    }
+
+    #[derive(Clone)]
+    struct FailingStore;
+    impl IPQueryStore for FailingStore {
+        fn store(&self, _ip_info: Arc<IPInfo>) -> StoreFuture {
+            Box::pin(async { Err(StoreError::Other("write failed".to_owned())) })
+        }
+    }
+
+    #[actix_web::test]
+    async fn store_failure_is_routed_through_on_error() {
+        let mut q = IPQuery::new(FailingStore);
+        q.cache(Duration::from_secs(60), 10);
+        q.on_error(FailurePolicy::FailClosed {
+            status: StatusCode::BAD_GATEWAY,
+        });
+        let ip_query = q.finish();
+        ip_query.cache.as_ref().unwrap().insert(
+            "127.0.0.1".to_owned(),
+            Arc::new(ip_info(Some("US"), false, false, false)),
+        );
+
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .wrap(ip_query)
+                .route("/", actix_web::web::get().to(|| async { "ok" })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .peer_addr("127.0.0.1:12345".parse().unwrap())
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[derive(Clone)]
+    struct DelayedStore(Arc<Mutex<bool>>);
+    impl IPQueryStore for DelayedStore {
+        fn store(&self, _ip_info: Arc<IPInfo>) -> StoreFuture {
+            let done = self.0.clone();
+            Box::pin(async move {
+                actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+                *done.lock().unwrap() = true;
+                Ok(())
+            })
+        }
+    }
+
+    #[actix_web::test]
+    async fn spawn_store_does_not_block_the_response_on_a_slow_store() {
+        let done = Arc::new(Mutex::new(false));
+        let mut q = IPQuery::new(DelayedStore(done.clone()));
+        q.cache(Duration::from_secs(60), 10);
+        q.spawn_store(true);
+        let ip_query = q.finish();
+        ip_query.cache.as_ref().unwrap().insert(
+            "127.0.0.1".to_owned(),
+            Arc::new(ip_info(Some("US"), false, false, false)),
+        );
+
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .wrap(ip_query)
+                .route("/", actix_web::web::get().to(|| async { "ok" })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .peer_addr("127.0.0.1:12345".parse().unwrap())
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+
+        assert!(resp.status().is_success());
+        // The response came back well before the store's 200ms delay elapsed.
+        assert!(!*done.lock().unwrap());
+
+        actix_web::rt::time::sleep(Duration::from_millis(300)).await;
+        assert!(*done.lock().unwrap());
+    }
 }